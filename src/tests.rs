@@ -0,0 +1,1712 @@
+//! Integration tests against a throwaway git repository.
+//!
+//! Every test builds its own bare `origin.git` in a temp dir (needs `git` on
+//! `PATH`), points a single-instance config at it and syncs like `main` does.
+
+use super::*;
+
+// ---------- Harness ----------
+
+/// Source checkout + bare `origin.git` the server clones from.
+struct TestRepo {
+    dir: tempfile::TempDir,
+}
+
+impl TestRepo {
+    fn new() -> Self {
+        let repo = TestRepo {
+            dir: tempfile::tempdir().expect("temp dir"),
+        };
+        let origin = repo.origin();
+        git_in(
+            repo.dir.path(),
+            &["init", "-q", "--bare", "-b", "main", path_str(&origin)],
+        );
+        git_in(repo.dir.path(), &["init", "-q", "-b", "main", "src"]);
+        repo.git(&["remote", "add", "origin", path_str(&origin)]);
+        repo
+    }
+
+    fn src(&self) -> PathBuf {
+        self.dir.path().join("src")
+    }
+
+    fn origin(&self) -> PathBuf {
+        self.dir.path().join("origin.git")
+    }
+
+    fn workdir(&self) -> PathBuf {
+        self.dir.path().join("work")
+    }
+
+    fn git(&self, args: &[&str]) -> String {
+        git_in(&self.src(), args)
+    }
+
+    /// Writes `files` on the current branch, commits and pushes; returns the sha.
+    fn commit(&self, files: &[(&str, &str)], message: &str) -> String {
+        for (path, content) in files {
+            let full = self.src().join(path);
+            std::fs::create_dir_all(full.parent().unwrap()).unwrap();
+            std::fs::write(full, content).unwrap();
+        }
+        self.git(&["add", "-A"]);
+        self.git(&["commit", "-q", "-m", message]);
+        self.git(&["push", "-q", "origin", "HEAD"]);
+        self.git(&["rev-parse", "HEAD"]).trim().to_string()
+    }
+
+    /// Commits `files` on a new branch forked from `main`, then returns to `main`.
+    fn branch(&self, name: &str, files: &[(&str, &str)]) -> String {
+        self.git(&["checkout", "-q", "-b", name]);
+        let sha = self.commit(files, &format!("{name} branch"));
+        self.git(&["checkout", "-q", "main"]);
+        sha
+    }
+
+    /// Single-instance config for this repo; `extra` is appended as root YAML.
+    fn config(&self, extra: &str) -> RootConfig {
+        serde_yaml_ng::from_str(&self.config_yaml(extra)).expect("test config")
+    }
+
+    fn config_yaml(&self, extra: &str) -> String {
+        format!(
+            "http:\n  bind_addr: \"127.0.0.1:0\"\n\
+             git:\n  repo_url: \"file://{}\"\n  branch: main\n  workdir: \"{}\"\n{extra}",
+            self.origin().display(),
+            self.workdir().display(),
+        )
+    }
+
+    /// `reload_state` after writing `config.yaml` with `extra`.
+    async fn reload(&self, current: &AppState, extra: &str) -> Arc<AppState> {
+        let path = self.dir.path().join("config.yaml");
+        std::fs::write(&path, self.config_yaml(extra)).unwrap();
+        let cli = Cli::parse_from(["simple-config-server", "--config", path_str(&path)]);
+        Arc::new(reload_state(&cli, current).await.expect("reload"))
+    }
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("utf-8 temp path")
+}
+
+fn git_in(dir: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("git on PATH");
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Builds and syncs the environments of `root_cfg`.
+async fn synced_envs(root_cfg: &RootConfig) -> HashMap<String, EnvState> {
+    let envs = build_envs(root_cfg).expect("build_envs");
+    sync_all_envs(&envs, 1).await.expect("initial sync");
+    envs
+}
+
+/// Synced `default` environment of a single-instance config.
+async fn default_env(root_cfg: &RootConfig) -> EnvState {
+    synced_envs(root_cfg)
+        .await
+        .remove("default")
+        .expect("default env")
+}
+
+async fn test_state(root_cfg: &RootConfig) -> Arc<AppState> {
+    app_state(root_cfg, synced_envs(root_cfg).await)
+}
+
+fn app_state(root_cfg: &RootConfig, envs: HashMap<String, EnvState>) -> Arc<AppState> {
+    Arc::new(AppState {
+        http: root_cfg.http.clone(),
+        envs,
+        auth: AuthConfig::from_env_and_config(&root_cfg.auth),
+        startup_time: Utc::now(),
+        allow_validate: root_cfg.allow_validate,
+        lint_max_files: root_cfg.lint_max_files,
+        allow_template_preview: root_cfg.allow_template_preview,
+        env_case_insensitive: root_cfg.env_name_case_insensitive,
+        audit: None,
+        git_health: root_cfg.git_health.clone(),
+        request_slots: request_slots(&root_cfg.http),
+        retired: CancellationToken::new(),
+    })
+}
+
+async fn test_router(root_cfg: &RootConfig) -> Router {
+    build_router(test_state(root_cfg).await, true)
+}
+
+/// Sends `req` through the router: status, headers and the whole body.
+async fn send(router: &Router, req: Request) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let res = router.clone().oneshot(req).await.unwrap();
+    let (parts, body) = res.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    (parts.status, parts.headers, body.to_vec())
+}
+
+async fn get(router: &Router, uri: &str) -> (StatusCode, Vec<u8>) {
+    let req = Request::get(uri).body(Body::empty()).unwrap();
+    let (status, _, body) = send(router, req).await;
+    (status, body)
+}
+
+async fn get_json(router: &Router, uri: &str) -> (StatusCode, JsonValue) {
+    let (status, body) = get(router, uri).await;
+    (status, json_body(&body))
+}
+
+async fn post_json(router: &Router, uri: &str) -> (StatusCode, JsonValue) {
+    let req = Request::post(uri).body(Body::empty()).unwrap();
+    let (status, _, body) = send(router, req).await;
+    (status, json_body(&body))
+}
+
+fn json_body(body: &[u8]) -> JsonValue {
+    serde_json::from_slice(body).unwrap_or(JsonValue::Null)
+}
+
+fn sample_repo() -> TestRepo {
+    let repo = TestRepo::new();
+    repo.commit(
+        &[
+            ("application.yml", "shared:\n  level: base\n"),
+            ("app.yml", "server:\n  port: 8080\nfeature: off\n"),
+            ("app-prod.yml", "server:\n  port: 9090\n"),
+            ("dev/notes.txt", "hello\n"),
+        ],
+        "initial",
+    );
+    repo
+}
+
+// ---------- Git access ----------
+
+#[tokio::test]
+async fn read_file_from_git_reads_default_branch_and_labels() {
+    let repo = sample_repo();
+    repo.branch("release", &[("app.yml", "server:\n  port: 7070\n")]);
+    let env = default_env(&repo.config("")).await;
+
+    let main = read_file_from_git(&env.git, None, Path::new("app.yml"))
+        .await
+        .unwrap()
+        .expect("app.yml on main");
+    assert!(String::from_utf8(main).unwrap().contains("8080"));
+
+    let release = read_file_from_git(&env.git, Some("release"), Path::new("app.yml"))
+        .await
+        .unwrap()
+        .expect("app.yml on release");
+    assert!(String::from_utf8(release).unwrap().contains("7070"));
+
+    let missing = read_file_from_git(&env.git, None, Path::new("nope.yml"))
+        .await
+        .unwrap();
+    assert!(missing.is_none());
+}
+
+#[tokio::test]
+async fn list_files_in_git_filters_by_prefix() {
+    let repo = sample_repo();
+    let env = default_env(&repo.config("")).await;
+
+    let all = list_files_in_git(&env.git, None, None).await.unwrap();
+    assert_eq!(
+        all,
+        [
+            "app-prod.yml",
+            "app.yml",
+            "application.yml",
+            "dev/notes.txt"
+        ]
+    );
+
+    let dev = list_files_in_git(&env.git, None, Some("dev/"))
+        .await
+        .unwrap();
+    assert_eq!(dev, ["dev/notes.txt"]);
+}
+
+#[tokio::test]
+async fn git_version_for_label_resolves_commits() {
+    let repo = sample_repo();
+    let head = repo.git(&["rev-parse", "HEAD"]).trim().to_string();
+    let release = repo.branch("release", &[("extra.yml", "a: 1\n")]);
+    let env = default_env(&repo.config("")).await;
+
+    assert_eq!(git_version_for_label(&env.git, None).await.unwrap(), head);
+    assert_eq!(
+        git_version_for_label(&env.git, Some("release"))
+            .await
+            .unwrap(),
+        release
+    );
+}
+
+#[tokio::test]
+async fn subpath_accepts_both_separator_styles() {
+    let repo = sample_repo();
+    repo.commit(&[("cfg/dev/svc.yml", "port: 1\n")], "nested");
+
+    for subpath in ["cfg/dev", "cfg\\\\dev", "./cfg//dev/"] {
+        let env = default_env(&repo.config(&format!("  subpath: \"{subpath}\"\n"))).await;
+        assert_eq!(
+            env.git.git_subpath().as_deref(),
+            Some("cfg/dev"),
+            "{subpath}"
+        );
+        assert_eq!(
+            list_files_in_git(&env.git, None, None).await.unwrap(),
+            ["svc.yml"],
+            "{subpath}"
+        );
+        let bytes = read_file_from_git(&env.git, None, Path::new("svc.yml"))
+            .await
+            .unwrap();
+        assert_eq!(bytes.as_deref(), Some(&b"port: 1\n"[..]), "{subpath}");
+    }
+}
+
+// ---------- Merge ----------
+
+#[test]
+fn empty_collections_are_emitted_on_request() {
+    let text = "list: []\nmap: {}\nnested:\n  inner: []\nfull: [1]\n";
+    for numbers in [NumberMode::Lossy, NumberMode::Preserve] {
+        let omit = YamlConfig {
+            numbers,
+            ..Default::default()
+        };
+        let flat = parse_and_flatten_yaml(text, &omit, "t.yml").unwrap();
+        assert_eq!(flat.keys().collect::<Vec<_>>(), ["full[0]"], "{numbers:?}");
+
+        let emit = YamlConfig {
+            numbers,
+            empty_collections: EmptyCollections::Emit,
+            ..Default::default()
+        };
+        let flat = parse_and_flatten_yaml(text, &emit, "t.yml").unwrap();
+        assert_eq!(flat["list"], serde_json::json!([]), "{numbers:?}");
+        assert_eq!(flat["map"], serde_json::json!({}), "{numbers:?}");
+        assert_eq!(flat["nested.inner"], serde_json::json!([]), "{numbers:?}");
+        assert_eq!(flat["full[0]"], 1, "{numbers:?}");
+    }
+}
+
+#[test]
+fn list_merge_keeps_one_list_per_key() {
+    let docs = || {
+        vec![
+            serde_json::json!({"a": {"list": [3]}}),
+            serde_json::json!({"a": {"list": [1, 2]}, "b": 1}),
+        ]
+    };
+
+    let mut replaced = docs();
+    merge_lists(&mut replaced, ListMerge::Replace);
+    assert_eq!(replaced[0], serde_json::json!({"a": {"list": [3]}}));
+    assert_eq!(replaced[1], serde_json::json!({"b": 1}));
+
+    let mut appended = docs();
+    merge_lists(&mut appended, ListMerge::Append);
+    assert_eq!(appended[0], serde_json::json!({"a": {"list": [1, 2, 3]}}));
+    assert_eq!(appended[1], serde_json::json!({"b": 1}));
+}
+
+#[tokio::test]
+async fn profile_file_takes_precedence_over_base_files() {
+    let repo = sample_repo();
+    let env = default_env(&repo.config("")).await;
+    let version = git_version_for_label(&env.git, None).await.unwrap();
+
+    let (sources, candidates) =
+        read_and_merge_yaml_files(&env, "app", &["prod".to_string()], None, &version)
+            .await
+            .unwrap();
+
+    assert_eq!(sources.len(), 3);
+    assert_eq!(sources[0].source["server.port"], 9090);
+    assert_eq!(sources[1].source["server.port"], 8080);
+    assert_eq!(sources[2].source["shared.level"], "base");
+    assert!(
+        candidates
+            .iter()
+            .any(|c| c.file == "app-prod.yml" && c.found)
+    );
+}
+
+#[tokio::test]
+async fn always_profiles_rank_below_requested_profiles() {
+    let repo = sample_repo();
+    repo.commit(
+        &[("app-org.yml", "server:\n  port: 1\norg: baseline\n")],
+        "org",
+    );
+    let router = test_router(&repo.config("always_profiles: [org]\n")).await;
+    let source = |body: &JsonValue, key: &str| -> Vec<JsonValue> {
+        body["propertySources"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|ps| ps["source"].get(key).cloned())
+            .collect()
+    };
+
+    let (_, body) = get_json(&router, "/default/app/prod").await;
+    assert_eq!(body["profiles"], serde_json::json!(["prod"]));
+    assert_eq!(source(&body, "server.port"), [9090, 1, 8080]);
+    assert_eq!(source(&body, "org"), ["baseline"]);
+
+    let (_, body) = get_json(&router, "/default/app/default").await;
+    assert_eq!(source(&body, "server.port"), [1, 8080]);
+}
+
+#[tokio::test]
+async fn last_profile_wins_with_any_separator() {
+    let repo = sample_repo();
+    repo.commit(
+        &[
+            ("application-prod.yml", "region: prod\n"),
+            ("application-eu.yml", "region: eu\n"),
+        ],
+        "profiles",
+    );
+    let router = test_router(&repo.config("profile_separators: [\"-\"]\n")).await;
+
+    for (profile, region) in [("prod,eu", "eu"), ("eu,prod", "prod"), ("prod-eu", "eu")] {
+        let (status, body) = get_json(&router, &format!("/default/app/{profile}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["profiles"],
+            serde_json::json!(profile.split([',', '-']).collect::<Vec<_>>())
+        );
+        let winner = body["propertySources"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find_map(|ps| ps["source"].get("region"));
+        assert_eq!(winner.unwrap(), region, "{profile}");
+    }
+    assert_eq!(parse_profiles("prod-eu", &[]), ["prod-eu"]);
+}
+
+#[tokio::test]
+async fn application_named_application_is_merged_once() {
+    let repo = sample_repo();
+    let env = default_env(&repo.config("")).await;
+    let version = git_version_for_label(&env.git, None).await.unwrap();
+
+    let (sources, candidates) = read_and_merge_yaml_files(
+        &env,
+        "application",
+        &["default".to_string()],
+        None,
+        &version,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sources.len(), 1);
+    let files: Vec<_> = candidates.iter().map(|c| c.file.as_str()).collect();
+    let unique: HashSet<_> = files.iter().collect();
+    assert_eq!(files.len(), unique.len(), "duplicate candidates: {files:?}");
+}
+
+#[tokio::test]
+async fn fallback_application_replaces_applications_without_files() {
+    let repo = sample_repo();
+    repo.commit(&[("defaults.yml", "feature: on\n")], "defaults");
+    let env = default_env(&repo.config("fallback_application: defaults\n")).await;
+    let version = git_version_for_label(&env.git, None).await.unwrap();
+    let profiles = ["prod".to_string()];
+
+    let (sources, candidates) =
+        read_and_merge_yaml_files(&env, "unknown", &profiles, None, &version)
+            .await
+            .unwrap();
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources[0].source["feature"], "on");
+    assert_eq!(sources[1].source["shared.level"], "base");
+    assert!(
+        candidates
+            .iter()
+            .any(|c| c.file == "defaults.yml" && c.found)
+    );
+
+    // Applications with files of their own are unaffected
+    let (sources, _) = read_and_merge_yaml_files(&env, "app", &profiles, None, &version)
+        .await
+        .unwrap();
+    assert_eq!(sources.len(), 3);
+    assert_eq!(sources[1].source["feature"], "off");
+}
+
+#[tokio::test]
+async fn missing_application_without_fallback_gets_shared_files_only() {
+    let repo = sample_repo();
+    let env = default_env(&repo.config("")).await;
+    let version = git_version_for_label(&env.git, None).await.unwrap();
+
+    let (sources, _) =
+        read_and_merge_yaml_files(&env, "unknown", &["prod".to_string()], None, &version)
+            .await
+            .unwrap();
+    assert_eq!(sources.len(), 1);
+    assert_eq!(sources[0].source["shared.level"], "base");
+}
+
+#[test]
+fn placeholders_resolve_keys_env_and_defaults() {
+    let source = |name: &str, pairs: &[(&str, JsonValue)]| SpringPropertySource {
+        name: name.to_string(),
+        source: pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect(),
+    };
+    let mut sources = vec![
+        source(
+            "high",
+            &[
+                ("host", "high.example.com".into()),
+                ("url", "http://${host}:${port:8080}/${path}".into()),
+                ("region", "${REGION}".into()),
+                ("missing", "${nope}".into()),
+            ],
+        ),
+        source(
+            "low",
+            &[
+                ("host", "low.example.com".into()),
+                ("path", "${prefix}/api".into()),
+                ("prefix", "v1".into()),
+                ("port", JsonValue::Null),
+            ],
+        ),
+    ];
+    let env = HashMap::from([("REGION".to_string(), "eu".to_string())]);
+    resolve_placeholders(&mut sources, &env).unwrap();
+
+    assert_eq!(sources[0].source["url"], "http://high.example.com:/v1/api");
+    assert_eq!(sources[0].source["region"], "eu");
+    assert_eq!(sources[0].source["missing"], "${nope}");
+    assert_eq!(sources[1].source["path"], "v1/api");
+
+    let merged = HashMap::from([("a".to_string(), JsonValue::from(1))]);
+    let mut stack = Vec::new();
+    assert_eq!(
+        resolve_placeholder_str("${a}-${b:two}-${c: x}", &merged, &env, &mut stack).unwrap(),
+        "1-two- x"
+    );
+}
+
+#[tokio::test]
+async fn placeholder_cycle_is_a_422_naming_the_chain() {
+    let repo = TestRepo::new();
+    repo.commit(
+        &[("app.yml", "a: ${b}\nb: x-${c}\nc: ${a}\nok: fine\n")],
+        "cycle",
+    );
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) = get_json(&router, "/default/app/default").await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        body["message"],
+        "Placeholder error: circular reference: a -> b -> c -> a"
+    );
+
+    let (status, body) = get_json(&router, "/default/app/default/property/ok").await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["message"].as_str().unwrap().contains("circular"));
+
+    let (status, _) = get_json(&router, "/default/app/default?resolveNested=false").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+// ---------- HTTP ----------
+
+#[tokio::test]
+async fn spring_endpoint_serves_merged_sources() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) = get_json(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["name"], "app");
+    assert_eq!(body["profiles"], serde_json::json!(["prod"]));
+    assert!(body["label"].is_null());
+    assert_eq!(body["propertySources"].as_array().unwrap().len(), 3);
+    assert_eq!(body["propertySources"][0]["source"]["server.port"], 9090);
+}
+
+#[tokio::test]
+async fn spring_endpoint_reads_explicit_label() {
+    let repo = sample_repo();
+    repo.branch("release", &[("app.yml", "server:\n  port: 7070\n")]);
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) = get_json(&router, "/default/app/default/release").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["label"], "release");
+    assert_eq!(body["propertySources"][0]["source"]["server.port"], 7070);
+}
+
+#[tokio::test]
+async fn committer_fields_follow_the_served_commit() {
+    let repo = sample_repo();
+    let root_cfg = repo.config("include_committer: true\n");
+    let router = test_router(&root_cfg).await;
+
+    let (status, body) = get_json(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["committerName"], "test");
+    assert_eq!(body["committerEmail"], "test@example.com");
+
+    let env_state = default_env(&root_cfg).await;
+    let unknown = "0".repeat(40);
+    let body = handle_spring_request(
+        &env_state,
+        "app",
+        vec!["prod".to_string()],
+        None,
+        unknown,
+        true,
+    )
+    .await
+    .unwrap();
+    let body = serde_json::to_value(body).unwrap();
+    assert_eq!(body["propertySources"][0]["source"]["server.port"], 9090);
+    assert!(body.get("committerName").is_none());
+    assert!(body.get("committerEmail").is_none());
+}
+
+#[tokio::test]
+async fn unknown_environment_is_not_found() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+
+    let (status, _) = get(&router, "/nope/app/prod").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn status_codes_follow_spring() {
+    let repo = TestRepo::new();
+    repo.commit(&[("app.yml", "server:\n  port: 8080\n")], "initial");
+    let router = test_router(&repo.config("")).await;
+
+    for uri in [
+        "/nope/app/default",
+        "/default/app/default/nope",
+        "/default/app/default/..",
+    ] {
+        let (status, body) = get_json(&router, uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        assert_eq!(body["status"], 404, "{uri}");
+    }
+
+    let (status, body) = get_json(&router, "/default/unknown/default").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["propertySources"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn assets_are_listed_and_served() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) = get_json(&router, "/default/assets?prefix=dev/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["files"], serde_json::json!(["dev/notes.txt"]));
+
+    let (status, body) = get(&router, "/default/assets/dev/notes.txt").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"hello\n");
+
+    let (status, _) = get(&router, "/default/assets/dev/missing.txt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn gitattributes_are_read_at_the_label() {
+    let repo = sample_repo();
+    repo.branch("crlf", &[(".gitattributes", "*.txt eol=crlf\n")]);
+    let router = test_router(&repo.config("  line_endings: gitattributes\n")).await;
+
+    let (status, body) = get(&router, "/default/assets/crlf/dev/notes.txt").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"hello\r\n");
+
+    let (status, body) = get(&router, "/default/assets/dev/notes.txt").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"hello\n");
+}
+
+#[tokio::test]
+async fn archive_route_keeps_application_archive_reachable() {
+    let repo = sample_repo();
+    repo.commit(
+        &[
+            ("archive.yml", "kind: archive\n"),
+            ("archive-prod.yml", "kind: archive-prod\n"),
+        ],
+        "archive app",
+    );
+    let router = test_router(&repo.config("")).await;
+
+    let (status, headers, body) = send(
+        &router,
+        Request::get("/default/archive/main.tar.gz")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[CONTENT_TYPE], "application/gzip");
+    assert_eq!(&body[..2], [0x1f, 0x8b]);
+
+    for uri in ["/default/archive/prod", "/default/archive/prod/main"] {
+        let (status, body) = get_json(&router, uri).await;
+        assert_eq!(status, StatusCode::OK, "{uri}");
+        assert_eq!(body["name"], "archive", "{uri}");
+        assert_eq!(body["propertySources"][0]["source"]["kind"], "archive-prod");
+    }
+
+    let (status, body) = get(&router, "/default/archive/prod.yml").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        String::from_utf8(body)
+            .unwrap()
+            .starts_with("kind: archive-prod\n")
+    );
+
+    let (status, body) = get_json(&router, "/default/archive/prod/property/kind").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "archive-prod");
+
+    let (status, body) = get_json(&router, "/nope/archive/prod").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["path"], "/nope/archive/prod");
+}
+
+#[tokio::test]
+async fn file_history_lists_commits_of_a_label() {
+    let repo = sample_repo();
+    let second = repo.commit(&[("dev/notes.txt", "bye\n")], "Update notes");
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) =
+        get_json(&router, "/default/file/main/dev/notes.txt/history?limit=1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["path"], "dev/notes.txt");
+    assert_eq!(body["label"], "main");
+    assert_eq!(body["commits"].as_array().unwrap().len(), 1);
+    assert_eq!(body["commits"][0]["sha"], second.as_str());
+    assert_eq!(body["commits"][0]["message"], "Update notes");
+
+    let (status, _) = get(&router, "/default/file/nope/app.yml/history").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn file_history_rejects_option_labels() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+    let target = repo.dir.path().join("injected");
+
+    let uri = format!(
+        "/default/file/--output={}/app.yml/history",
+        target.display()
+    );
+    let (status, _) = get(&router, &uri).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn file_history_keeps_application_file_reachable() {
+    let repo = sample_repo();
+    repo.commit(&[("file.yml", "kind: file\n")], "file app");
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) = get_json(&router, "/default/file/default/main").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["propertySources"][0]["source"]["kind"], "file");
+
+    let (status, body) = get_json(&router, "/default/file/default/property/kind").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "file");
+
+    // Neither a history path nor a Spring one
+    let (status, body) = get_json(&router, "/default/file/main/dev/notes.txt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["path"], "/default/file/main/dev/notes.txt");
+}
+
+#[tokio::test]
+async fn validate_is_gated_before_the_env_lookup() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+
+    let (status, body) = post_json(&router, "/nope/validate").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["message"], "Validation endpoint is disabled");
+}
+
+#[tokio::test]
+async fn validate_rejects_option_labels() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("allow_validate: true\n")).await;
+    let target = repo.dir.path().join("injected");
+
+    let uri = format!(
+        "/default/validate?application=app&label=--output={}",
+        target.display()
+    );
+    let (status, _) = post_json(&router, &uri).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(!target.exists());
+
+    let (status, body) = post_json(&router, "/default/validate?application=app&label=main").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["valid"], true);
+}
+
+#[tokio::test]
+async fn validate_reports_unresolved_names() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("allow_validate: true\n")).await;
+
+    let req = Request::post("/default/validate?application=app")
+        .body(Body::from(
+            "db: \"{{ DB_PASSWORD }}\"\nurl: \"http://x:${missing.port}\"\n",
+        ))
+        .unwrap();
+    let (status, _, body) = send(&router, req).await;
+    assert_eq!(status, StatusCode::OK);
+    let body = json_body(&body);
+    assert_eq!(body["valid"], false);
+    assert_eq!(
+        body["unresolvedVariables"],
+        serde_json::json!(["DB_PASSWORD"])
+    );
+    assert_eq!(
+        body["unresolvedPlaceholders"],
+        serde_json::json!(["${missing.port}"])
+    );
+}
+
+#[tokio::test]
+async fn deleted_workdir_is_recloned_on_demand() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+    std::fs::remove_dir_all(repo.workdir()).unwrap();
+
+    let (first, second) = tokio::join!(
+        get_json(&router, "/default/app/prod"),
+        get_json(&router, "/default/app/prod"),
+    );
+    for (status, body) in [first, second] {
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["propertySources"][0]["source"]["server.port"], 9090);
+    }
+    assert!(repo.workdir().join(".git/HEAD").exists());
+}
+
+#[tokio::test]
+async fn unrecoverable_workdir_answers_503_with_retry_after() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+    std::fs::remove_dir_all(repo.workdir()).unwrap();
+    std::fs::remove_dir_all(repo.origin()).unwrap();
+
+    let (status, headers, body) = send(
+        &router,
+        Request::get("/default/app/prod")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(headers[RETRY_AFTER], "5");
+    assert_eq!(
+        json_body(&body)["message"],
+        "Git workdir is being re-initialized, retry later"
+    );
+}
+
+#[tokio::test]
+async fn environment_answers_503_until_first_sync() {
+    let repo = sample_repo();
+    let root_cfg = repo.config("");
+    let envs = build_envs(&root_cfg).expect("build_envs");
+    let router = build_router(app_state(&root_cfg, envs.clone()), true);
+
+    for uri in ["/default/app/prod", "/default/assets/dev/notes.txt"] {
+        let (status, headers, body) =
+            send(&router, Request::get(uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{uri}");
+        assert_eq!(headers[RETRY_AFTER], "5", "{uri}");
+        assert_eq!(
+            json_body(&body)["message"],
+            "Environment is not ready (initial sync pending), retry later",
+            "{uri}"
+        );
+    }
+
+    sync_all_envs(&envs, 1).await.expect("initial sync");
+    let (status, body) = get_json(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["propertySources"][0]["source"]["server.port"], 9090);
+}
+
+#[tokio::test]
+async fn repo_manifest_is_opt_in_and_tolerates_bad_yaml() {
+    let repo = sample_repo();
+    repo.commit(
+        &[
+            ("manifest.yml", "applications:\n  app: [custom.yml]\n"),
+            ("custom.yml", "server:\n  port: 1234\n"),
+        ],
+        "manifest",
+    );
+    let port = |body: &JsonValue| body["propertySources"][0]["source"]["server.port"].clone();
+
+    let router = test_router(&repo.config("")).await;
+    let (_, body) = get_json(&router, "/default/app/default").await;
+    assert_eq!(port(&body), 8080);
+
+    let router = test_router(&repo.config("repo_manifest: true\n")).await;
+    let (_, body) = get_json(&router, "/default/app/default").await;
+    assert_eq!(port(&body), 1234);
+
+    repo.commit(&[("manifest.yml", "applications: [\n")], "broken manifest");
+    let router = test_router(&repo.config("repo_manifest: true\n")).await;
+    let (status, body) = get_json(&router, "/default/app/default").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(port(&body), 8080);
+}
+
+#[tokio::test]
+async fn profile_branches_apply_to_validate_and_ws() {
+    let repo = sample_repo();
+    repo.branch("release", &[("app-prod.yml", "origin: release\n")]);
+    let root_cfg = repo.config("  profile_branches:\n    prod: release\nallow_validate: true\n");
+    let router = test_router(&root_cfg).await;
+
+    let req = Request::post("/default/validate?application=app&profile=prod")
+        .body(Body::from("copy: \"${origin}\"\n"))
+        .unwrap();
+    let (status, _, body) = send(&router, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json_body(&body)["keys"]["copy"], "release");
+
+    let env_state = default_env(&root_cfg).await;
+    let params = WsParams {
+        application: Some("app".to_string()),
+        profile: "prod".to_string(),
+        resolve_nested: true,
+    };
+    let change = ConfigChange {
+        env: "default".to_string(),
+        commit: String::new(),
+        previous: None,
+    };
+    let msg: JsonValue =
+        serde_json::from_str(&ws_message(&env_state, &params, "ready", change).await).unwrap();
+    assert_eq!(
+        msg["config"]["propertySources"][0]["source"]["origin"],
+        "release"
+    );
+}
+
+#[tokio::test]
+async fn event_streams_end_when_the_state_is_retired() {
+    let repo = sample_repo();
+    let state = test_state(&repo.config("")).await;
+    let router = build_router(state.clone(), true);
+
+    let req = Request::get("/default/events").body(Body::empty()).unwrap();
+    let res = router.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    state.retired.cancel();
+    let body = tokio::time::timeout(
+        Duration::from_secs(5),
+        axum::body::to_bytes(res.into_body(), usize::MAX),
+    )
+    .await
+    .expect("stream ended")
+    .unwrap();
+    assert!(String::from_utf8_lossy(&body).starts_with("event: ready"));
+}
+
+#[tokio::test]
+async fn negotiated_responses_carry_vary() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config(
+        "auth:\n  client_id:\n    enabled: true\n    clients:\n      - { id: ci, environments: [\"*\"], scopes: [\"config:read\"] }\n",
+    ))
+    .await;
+
+    for accept in ["text/html", "application/json"] {
+        let req = Request::get("/no/such/route/here/at/all")
+            .header(ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(&router, req).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers[VARY], "accept", "{accept}");
+    }
+
+    let req = Request::get("/default/app/prod")
+        .header("x-client-id", "ci")
+        .body(Body::empty())
+        .unwrap();
+    let (status, headers, _) = send(&router, req).await;
+    assert_eq!(status, StatusCode::OK);
+    let vary: Vec<&str> = headers
+        .get_all(VARY)
+        .iter()
+        .map(|v| v.to_str().unwrap())
+        .collect();
+    assert!(vary.contains(&"x-client-id"), "{vary:?}");
+}
+
+#[tokio::test]
+async fn disabled_endpoints_cover_property_preview_and_label_listings() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config(
+        "allow_template_preview: true\ndisabled_endpoints: [property, preview, asset_labels]\n",
+    ))
+    .await;
+
+    for uri in [
+        "/default/app/prod/property/server.port",
+        "/default/preview/app/prod",
+        "/default/assets?label=main",
+    ] {
+        let (status, body) = get_json(&router, uri).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{uri}");
+        assert_eq!(body["message"], "Endpoint disabled for this environment");
+    }
+    let (status, _) = get(&router, "/default/assets").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn template_preview_lists_substitutions_per_file() {
+    let repo = sample_repo();
+    repo.commit(
+        &[(
+            "svc.yml",
+            "host: \"{{ DB_HOST }}\"\nport: \"{{ DB_PORT }}\"\n",
+        )],
+        "svc",
+    );
+    let env_file = repo.dir.path().join("global.env");
+    std::fs::write(&env_file, "DB_HOST=db.local\n").unwrap();
+    let extra = format!(
+        "allow_template_preview: true\nenv_file: \"{}\"\n",
+        env_file.display()
+    );
+    let router = test_router(&repo.config(&extra)).await;
+
+    let (status, body) = get_json(&router, "/default/preview/svc/default").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["files"][0]["file"], "svc.yml");
+    assert_eq!(body["files"][0]["substituted"]["DB_HOST"], "db.local");
+    assert_eq!(body["unresolvedVariables"], serde_json::json!(["DB_PORT"]));
+
+    // Now an ordinary label
+    let (status, _) = get(&router, "/default/svc/default/preview").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn trailing_slash_is_ignored_by_default() {
+    let repo = sample_repo();
+    let root_cfg = repo.config("");
+    assert_eq!(root_cfg.http.trailing_slash, TrailingSlash::Ignore);
+    let router = test_router(&root_cfg).await;
+
+    let (status, body) = get(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+    let (slash_status, slash_body) = get(&router, "/default/app/prod/").await;
+    assert_eq!(slash_status, StatusCode::OK);
+    assert_eq!(slash_body, body);
+}
+
+#[tokio::test]
+async fn trailing_slash_redirect_stays_on_this_host() {
+    let repo = sample_repo();
+    let mut root_cfg = repo.config("");
+    root_cfg.http.trailing_slash = TrailingSlash::Redirect;
+    let router = test_router(&root_cfg).await;
+
+    for (uri, location) in [
+        ("/default/app/prod/", "/default/app/prod"),
+        ("//evil.com/", "/evil.com"),
+        ("///evil.com//?a=1", "/evil.com?a=1"),
+    ] {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let (status, headers, _) = send(&router, req).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT, "{uri}");
+        assert_eq!(headers[LOCATION], location, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn properties_output_is_escaped_like_properties_store() {
+    let repo = sample_repo();
+    repo.commit(
+        &[(
+            "props.yml",
+            "name: Žluťoučký\npath: 'C:\\temp'\nlines: \"a\\nb\"\nurl: \" x=1#!\"\n",
+        )],
+        "props",
+    );
+
+    let router = test_router(&repo.config("")).await;
+    let (status, body) = get(&router, "/default/props/default.properties").await;
+    assert_eq!(status, StatusCode::OK);
+    let text = String::from_utf8(body).unwrap();
+    assert!(text.contains("name: Žluťoučký\n"), "{text}");
+    assert!(text.contains("path: C\\:\\\\temp\n"), "{text}");
+    assert!(text.contains("lines: a\\nb\n"), "{text}");
+    assert!(text.contains("url: \\ x\\=1\\#\\!\n"), "{text}");
+
+    let router = test_router(&repo.config("properties_unicode_escape: true\n")).await;
+    let (_, body) = get(&router, "/default/props/default.properties").await;
+    let text = String::from_utf8(body).unwrap();
+    assert!(
+        text.contains("name: \\u017Dlu\\u0165ou\\u010Dk\\u00FD\n"),
+        "{text}"
+    );
+    assert!(text.contains("path: C\\:\\\\temp\n"), "{text}");
+}
+
+#[tokio::test]
+async fn compressed_cache_is_not_shared_between_envs() {
+    let repo = sample_repo();
+    repo.commit(&[("override.yml", "server:\n  port: 1\n")], "override");
+    let env = |name: &str, extra: &str| {
+        format!(
+            "  {name}:\n    git:\n      repo_url: \"file://{}\"\n      branch: main\n      workdir: \"{}\"\n{extra}",
+            repo.origin().display(),
+            repo.dir.path().join(name).display(),
+        )
+    };
+    let yaml = format!(
+        "http:\n  bind_addr: \"127.0.0.1:0\"\n  compression: [gzip]\n  compression_cache_entries: 8\n\
+         environments:\n{}{}",
+        env("plain", ""),
+        env("patched", "    override_file: override.yml\n"),
+    );
+    let router = test_router(&serde_yaml_ng::from_str(&yaml).unwrap()).await;
+
+    let mut ports = Vec::new();
+    for uri in ["/plain/app/prod", "/patched/app/prod", "/plain/app/prod"] {
+        let req = Request::get(uri)
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&router, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        let mut plain = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&body[..]), &mut plain)
+            .unwrap();
+        ports.push(json_body(&plain)["propertySources"][0]["source"]["server.port"].clone());
+    }
+    assert_eq!(ports, [9090, 1, 9090]);
+}
+
+// ---------- KMS ----------
+
+/// Stub `aws` CLI: logs each call, "decrypts" `X` to `plain-X` (base64, like
+/// `--query Plaintext`) and fails for the ciphertext `bad`.
+fn stub_aws(dir: &Path) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+    let script = dir.join("aws");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\n\
+         echo \"$@\" >> \"$0.log\"\n\
+         while [ $# -gt 0 ]; do [ \"$1\" = --ciphertext-blob ] && blob=$2; shift; done\n\
+         [ \"$blob\" = bad ] && { echo 'InvalidCiphertextException' >&2; exit 1; }\n\
+         printf 'plain-%s' \"$blob\" | base64\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+#[tokio::test]
+async fn kms_values_are_decrypted_once_per_ciphertext() {
+    let repo = TestRepo::new();
+    repo.commit(
+        &[(
+            "app.yml",
+            "password: \"{enc}c1\"\nalias: \"{enc} c1\"\ntoken: \"{enc}c2\"\nplain: text\n",
+        )],
+        "initial",
+    );
+    repo.branch("release", &[("app-prod.yml", "token: \"{enc}c3\"\n")]);
+    repo.commit(&[("broken.yml", "secret: \"{enc}bad\"\n")], "broken");
+    let aws = stub_aws(repo.dir.path());
+    let router = test_router(&repo.config(&format!(
+        "kms:\n  key_id: test-key\n  aws_binary: \"{}\"\n",
+        aws.display()
+    )))
+    .await;
+    let calls = || {
+        std::fs::read_to_string(aws.with_extension("log"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    };
+
+    for _ in 0..2 {
+        let (status, body) = get_json(&router, "/default/app/default").await;
+        assert_eq!(status, StatusCode::OK);
+        let source = &body["propertySources"][0]["source"];
+        assert_eq!(source["password"], "plain-c1");
+        assert_eq!(source["alias"], "plain-c1");
+        assert_eq!(source["token"], "plain-c2");
+        assert_eq!(source["plain"], "text");
+
+        let (status, body) = get_json(&router, "/default/app/prod/release").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["propertySources"][0]["source"]["token"], "plain-c3");
+        assert_eq!(body["propertySources"][1]["source"]["token"], "plain-c2");
+    }
+    assert_eq!(calls(), 3);
+    let log = std::fs::read_to_string(aws.with_extension("log")).unwrap();
+    assert!(log.contains("kms decrypt --key-id test-key --ciphertext-blob c1"));
+
+    let (status, _) = get_json(&router, "/default/broken/default").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+// ---------- Env map ----------
+
+#[test]
+fn env_precedence_orders_process_env_and_env_files() {
+    let repo = TestRepo::new();
+    let env_file = repo.dir.path().join("global.env");
+    std::fs::write(&env_file, "PATH=from-file\nONLY_IN_FILE=x\n").unwrap();
+    let process_path = std::env::var("PATH").expect("PATH set");
+    let base = format!(
+        "env_from_process: true\nenv_file: \"{}\"\n",
+        env_file.display()
+    );
+
+    let envs = build_envs(&repo.config(&base)).unwrap();
+    assert_eq!(envs["default"].env_map["PATH"], "from-file");
+
+    let envs = build_envs(&repo.config(&format!("{base}env_precedence: process\n"))).unwrap();
+    assert_eq!(envs["default"].env_map["PATH"], process_path);
+    assert_eq!(envs["default"].env_map["ONLY_IN_FILE"], "x");
+}
+
+// ---------- Templates ----------
+
+fn env_of(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn recursive_templates() -> TemplateSettings {
+    TemplateSettings {
+        recursive: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn nested_variables_need_recursive_mode() {
+    let env = env_of(&[("A", "{{B}}-a"), ("B", "{{ C }}"), ("C", "value")]);
+
+    assert_eq!(
+        TemplateSettings::default().apply("x={{A}}", &env),
+        "x={{B}}-a"
+    );
+    assert_eq!(recursive_templates().apply("x={{A}}", &env), "x=value-a");
+}
+
+#[test]
+fn recursive_templates_stop_at_cycles() {
+    let env = env_of(&[("A", "{{B}}"), ("B", "b{{A}}"), ("SELF", "{{SELF}}!")]);
+
+    assert_eq!(recursive_templates().apply("{{A}}", &env), "b{{A}}");
+    assert_eq!(recursive_templates().apply("{{SELF}}", &env), "{{SELF}}!");
+}
+
+#[test]
+fn recursive_templates_are_depth_bounded() {
+    let chain: Vec<(String, String)> = (0..=MAX_TEMPLATE_DEPTH + 1)
+        .map(|i| (format!("V{i}"), format!("{{{{V{}}}}}", i + 1)))
+        .collect();
+    let env: HashMap<String, String> = chain.into_iter().collect();
+
+    let out = recursive_templates().apply("{{V0}}", &env);
+    assert_eq!(out, format!("{{{{V{MAX_TEMPLATE_DEPTH}}}}}"));
+}
+
+#[test]
+fn recursive_templates_keep_escapes_of_values() {
+    let env = env_of(&[("A", "{{{{B}}}} {{B}}"), ("B", "b")]);
+
+    assert_eq!(recursive_templates().apply("{{A}}", &env), "{{B}} b");
+}
+
+#[tokio::test]
+async fn dynamic_var_tracking_is_scoped_to_one_render() {
+    let env = env_of(&[("A", "a")]);
+
+    let (out, used) =
+        tracking_dynamic_vars(async { TemplateSettings::default().apply("{{A}} {{UUID}}", &env) })
+            .await;
+    assert_eq!(out, "a {{UUID}}");
+    assert!(!used, "UUID is not enabled in tests");
+
+    let (_, used) = tracking_dynamic_vars(async {
+        DYNAMIC_USED.with(|used| used.set(true));
+    })
+    .await;
+    assert!(used);
+    assert!(DYNAMIC_USED.try_with(|used| used.get()).is_err());
+}
+
+async fn asset_text(state: &Arc<AppState>, path: &str) -> String {
+    let router = build_router(state.clone(), true);
+    let (status, body) = get(&router, &format!("/default/assets/{path}")).await;
+    assert_eq!(status, StatusCode::OK, "{path}");
+    String::from_utf8(body).unwrap()
+}
+
+#[tokio::test]
+async fn reload_applies_template_settings() {
+    let repo = sample_repo();
+    repo.commit(
+        &[
+            ("dev/greeting.txt", "{{ GREETING }} << GREETING >>\n"),
+            ("dev/host.txt", "{{ HOSTNAME }}\n"),
+            ("dev/nested.txt", "{{ NESTED }}\n"),
+        ],
+        "templates",
+    );
+    let env_file = repo.dir.path().join("global.env");
+    std::fs::write(&env_file, "GREETING=hi\nNESTED={{ GREETING }}!\n").unwrap();
+    let extra = format!("env_file: \"{}\"\n", env_file.display());
+    let state = test_state(&repo.config(&extra)).await;
+    assert_eq!(
+        asset_text(&state, "dev/greeting.txt").await,
+        "hi << GREETING >>\n"
+    );
+    assert_eq!(asset_text(&state, "dev/host.txt").await, "{{ HOSTNAME }}\n");
+    assert_eq!(
+        asset_text(&state, "dev/nested.txt").await,
+        "{{ GREETING }}!\n"
+    );
+
+    let delimiter = format!("{extra}templates:\n  delimiter: \"<< >>\"\n");
+    let reloaded = repo.reload(&state, &delimiter).await;
+    assert_eq!(
+        asset_text(&reloaded, "dev/greeting.txt").await,
+        "{{ GREETING }} hi\n"
+    );
+
+    let dynamic = format!("{extra}templates:\n  dynamic: [HOSTNAME]\n");
+    let reloaded = repo.reload(&state, &dynamic).await;
+    assert_eq!(
+        asset_text(&reloaded, "dev/host.txt").await,
+        format!("{}\n", *HOSTNAME)
+    );
+
+    let recursive = format!("{extra}templates:\n  recursive: true\n");
+    let reloaded = repo.reload(&state, &recursive).await;
+    assert_eq!(asset_text(&reloaded, "dev/nested.txt").await, "hi!\n");
+}
+
+#[tokio::test]
+async fn startup_check_scans_files_before_templating() {
+    let repo = sample_repo();
+    repo.commit(
+        &[(
+            "svc.yml",
+            "raw: \"{{{{ ESCAPED }}}}\"\nhost: \"{{ DB_HOST }}\"\n",
+        )],
+        "svc",
+    );
+    let envs = synced_envs(&repo.config("")).await;
+    assert!(startup_check(&envs, "app", "default").await);
+    assert!(!startup_check(&envs, "svc", "default").await);
+
+    repo.commit(
+        &[("svc.yml", "raw: \"{{{{ ESCAPED }}}}\"\n")],
+        "escaped only",
+    );
+    let envs = synced_envs(&repo.config("")).await;
+    assert!(startup_check(&envs, "svc", "default").await);
+}
+
+#[tokio::test]
+async fn lint_reports_unresolved_variables_per_file() {
+    let repo = sample_repo();
+    repo.commit(
+        &[
+            ("db.yml", "url: \"{{ DB_HOST }}:{{ DB_PORT }}\"\n"),
+            (
+                "dev/cache.yml",
+                "host: \"{{ DB_HOST }}\"\nraw: \"{{{{ ESCAPED }}}}\"\n",
+            ),
+            ("logo.bin", "\0{{ BINARY }}"),
+        ],
+        "templated files",
+    );
+    let router = test_router(&repo.config("allow_validate: true\n")).await;
+
+    let (status, body) = post_json(&router, "/default/lint").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["valid"], false);
+    assert_eq!(body["total"], 7);
+    assert_eq!(body["scanned"], 6);
+    assert_eq!(body["truncated"], false);
+    assert_eq!(
+        body["unresolvedVariables"],
+        serde_json::json!(["DB_HOST", "DB_PORT"])
+    );
+    assert_eq!(
+        body["files"],
+        serde_json::json!([
+            { "file": "db.yml", "unresolvedVariables": ["DB_HOST", "DB_PORT"] },
+            { "file": "dev/cache.yml", "unresolvedVariables": ["DB_HOST"] },
+        ])
+    );
+
+    let (_, body) = post_json(&router, "/default/lint?prefix=app").await;
+    assert_eq!(body["valid"], true);
+    assert_eq!(body["files"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn lint_is_capped_and_gated() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("allow_validate: true\nlint_max_files: 2\n")).await;
+
+    let (status, body) = post_json(&router, "/default/lint").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["scanned"], 2);
+    assert_eq!(body["truncated"], true);
+
+    let router = test_router(&repo.config("")).await;
+    let (status, _) = post_json(&router, "/default/lint").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = post_json(&router, "/nope/lint").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn saturated_server_answers_503_except_health() {
+    let repo = sample_repo();
+    let mut root_cfg = repo.config("");
+    root_cfg.http.max_concurrent_requests = 1;
+    let state = test_state(&root_cfg).await;
+    let router = build_router(state.clone(), true);
+
+    let busy = state
+        .request_slots
+        .clone()
+        .unwrap()
+        .acquire_owned()
+        .await
+        .unwrap();
+    let (status, body) = get_json(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["message"], "Too many concurrent requests, retry later");
+    let (status, _) = get(&router, "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+
+    drop(busy);
+    let (status, _) = get(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+// ---------- gRPC ----------
+
+/// `(field, tag, occurrences)` of an encoded message whose fields are all
+/// length-delimited (strings, repeated strings, string maps).
+fn wire_fields(mut bytes: &[u8]) -> Vec<(u32, usize)> {
+    fn varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+    let mut fields: Vec<(u32, usize)> = Vec::new();
+    while !bytes.is_empty() {
+        let key = varint(&mut bytes);
+        assert_eq!(key & 7, 2, "tag {} is not length-delimited", key >> 3);
+        let len = varint(&mut bytes) as usize;
+        bytes = &bytes[len..];
+        match fields.last_mut() {
+            Some((tag, count)) if u64::from(*tag) == key >> 3 => *count += 1,
+            _ => fields.push(((key >> 3) as u32, 1)),
+        }
+    }
+    fields
+}
+
+/// `(name, tag, occurrences)` of every field of a populated message: names
+/// from its `Debug` output, tags from its encoding (both in field order).
+fn rust_fields<M: prost::Message + std::fmt::Debug>(msg: &M) -> Vec<(String, u32, usize)> {
+    let names: Vec<String> = Regex::new(r"(\w+): ")
+        .unwrap()
+        .captures_iter(&format!("{msg:?}"))
+        .map(|caps| caps[1].to_string())
+        .collect();
+    let tags = wire_fields(&msg.encode_to_vec());
+    assert_eq!(names.len(), tags.len(), "{msg:?}");
+    names
+        .into_iter()
+        .zip(tags)
+        .map(|(name, (tag, count))| (name, tag, count))
+        .collect()
+}
+
+/// The same triples as declared in `proto/config.proto`; repeated and map
+/// fields count as two occurrences (the messages above set two entries).
+fn proto_fields(message: &str) -> Vec<(String, u32, usize)> {
+    let proto = include_str!("../proto/config.proto");
+    let start = proto
+        .find(&format!("message {message} {{"))
+        .expect("message in proto");
+    let body = &proto[start..start + proto[start..].find('}').unwrap()];
+    Regex::new(r"(?m)^\s*(optional |repeated )?(map<[^>]+>|\w+) (\w+) = (\d+);")
+        .unwrap()
+        .captures_iter(body)
+        .map(|caps| {
+            let many = caps.get(1).is_some_and(|m| m.as_str() == "repeated ")
+                || caps[2].starts_with("map<");
+            (
+                caps[3].to_string(),
+                caps[4].parse().unwrap(),
+                if many { 2 } else { 1 },
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn grpc_messages_match_the_proto_file() {
+    let two = || vec!["a".to_string(), "b".to_string()];
+    let request = GetConfigRequest {
+        env: "x".to_string(),
+        application: "x".to_string(),
+        profile: "x".to_string(),
+        label: Some("x".to_string()),
+    };
+    assert_eq!(rust_fields(&request), proto_fields("GetConfigRequest"));
+
+    let response = GetConfigResponse {
+        name: "x".to_string(),
+        profiles: two(),
+        label: Some("x".to_string()),
+        version: "x".to_string(),
+        properties: two().into_iter().map(|k| (k, "v".to_string())).collect(),
+        sources: two(),
+    };
+    assert_eq!(rust_fields(&response), proto_fields("GetConfigResponse"));
+}
+
+#[tokio::test]
+async fn grpc_get_config_merges_like_spring() {
+    let repo = sample_repo();
+    let release = repo.branch("release", &[("app.yml", "server:\n  port: 7070\n")]);
+    let state = test_state(&repo.config("")).await;
+    let request = |env: &str, label: Option<&str>| {
+        tonic::Request::new(GetConfigRequest {
+            env: env.to_string(),
+            application: "app".to_string(),
+            profile: "prod".to_string(),
+            label: label.map(str::to_string),
+        })
+    };
+
+    let config = grpc_get_config(&state, request("default", None))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(config.name, "app");
+    assert_eq!(config.profiles, ["prod"]);
+    assert_eq!(config.properties["server.port"], "9090");
+    assert_eq!(config.properties["feature"], "off");
+    assert_eq!(config.properties["shared.level"], "base");
+    assert_eq!(config.sources.len(), 3);
+
+    let config = grpc_get_config(&state, request("default", Some("release")))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(config.label.as_deref(), Some("release"));
+    assert_eq!(config.version, release);
+
+    for (env, label) in [("nope", None), ("default", Some("nope"))] {
+        let status = grpc_get_config(&state, request(env, label))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound, "{env} {label:?}");
+    }
+}
+
+#[tokio::test]
+async fn grpc_shares_the_request_slots() {
+    let repo = sample_repo();
+    let mut root_cfg = repo.config("");
+    root_cfg.http.max_concurrent_requests = 1;
+    let state = test_state(&root_cfg).await;
+    let router = build_grpc_router(state.clone());
+
+    let _busy = state
+        .request_slots
+        .clone()
+        .unwrap()
+        .acquire_owned()
+        .await
+        .unwrap();
+    let req = Request::post(GRPC_GET_CONFIG_PATH)
+        .header(CONTENT_TYPE, "application/grpc")
+        .body(Body::empty())
+        .unwrap();
+    let (_, headers, _) = send(&router, req).await;
+    assert_eq!(
+        headers["grpc-status"],
+        (tonic::Code::Unavailable as i32).to_string()
+    );
+
+    let same = reloaded_request_slots(&state, &root_cfg.http).unwrap();
+    assert!(Arc::ptr_eq(&same, state.request_slots.as_ref().unwrap()));
+    root_cfg.http.max_concurrent_requests = 2;
+    let changed = reloaded_request_slots(&state, &root_cfg.http).unwrap();
+    assert_eq!(changed.available_permits(), 2);
+}
+
+#[tokio::test]
+async fn audit_record_is_written_before_the_response() {
+    let repo = sample_repo();
+    let log = repo.dir.path().join("audit.log");
+    let root_cfg = repo.config(&format!("audit:\n  file: \"{}\"\n", log.display()));
+    let state = Arc::try_unwrap(test_state(&root_cfg).await).ok().unwrap();
+    let state = Arc::new(AppState {
+        audit: AuditSink::open(&root_cfg.audit).unwrap().map(Arc::new),
+        ..state
+    });
+    let router = build_router(state, true);
+
+    let (status, _) = get(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+    let written = std::fs::read_to_string(&log).unwrap();
+    let record: serde_json::Value = serde_json::from_str(written.trim()).unwrap();
+    assert_eq!(record["application"], "app");
+    assert_eq!(record["profile"], "prod");
+    assert_eq!(record["status"], 200);
+}
+
+#[tokio::test]
+async fn reload_applies_max_git_concurrency() {
+    let repo = sample_repo();
+    let state = test_state(&repo.config("max_git_concurrency: 2\n")).await;
+    let slots = |state: &AppState| state.envs["default"].git.runtime.slots.clone();
+
+    let same = repo.reload(&state, "max_git_concurrency: 2\n").await;
+    assert!(Arc::ptr_eq(
+        &slots(&state).unwrap().1,
+        &slots(&same).unwrap().1
+    ));
+
+    let changed = repo.reload(&state, "max_git_concurrency: 3\n").await;
+    assert_eq!(slots(&changed).unwrap().1.available_permits(), 3);
+
+    let unlimited = repo.reload(&state, "").await;
+    assert!(slots(&unlimited).is_none());
+
+    assert!(build_envs(&repo.config("max_git_concurrency: 0\n")).is_err());
+}
+
+#[tokio::test]
+async fn reload_applies_git_proxy() {
+    let repo = sample_repo();
+    let state = test_state(&repo.config("")).await;
+    let proxy = |state: &AppState| {
+        let cmd = git_command(&state.envs["default"].git.runtime);
+        cmd.as_std()
+            .get_envs()
+            .find(|(key, _)| *key == "https_proxy")
+            .and_then(|(_, value)| value.map(|v| v.to_string_lossy().into_owned()))
+    };
+    assert_eq!(proxy(&state), None);
+
+    let reloaded = repo
+        .reload(&state, "git_proxy: \"http://user:pw@proxy:3128\"\n")
+        .await;
+    assert_eq!(
+        proxy(&reloaded).as_deref(),
+        Some("http://user:pw@proxy:3128")
+    );
+    let runtime = format!("{:?}", reloaded.envs["default"].git.runtime);
+    assert!(runtime.contains("http://***@proxy:3128") && !runtime.contains("pw"));
+}
+
+// ---------- Last-known-good ----------
+
+#[tokio::test]
+async fn stale_revalidation_keeps_the_stale_marker() {
+    let repo = sample_repo();
+    let router = test_router(&repo.config("")).await;
+    let (status, _) = get(&router, "/default/app/prod").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The default label stops resolving, as after a broken sync
+    git_in(
+        &repo.workdir(),
+        &["update-ref", "-d", "refs/remotes/origin/main"],
+    );
+    git_in(&repo.workdir(), &["checkout", "-q", "--detach"]);
+    git_in(&repo.workdir(), &["branch", "-q", "-D", "main"]);
+
+    let req = Request::get("/default/app/prod")
+        .body(Body::empty())
+        .unwrap();
+    let (status, headers, _) = send(&router, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[X_CONFIG_STALE], "true");
+
+    let req = Request::get("/default/app/prod")
+        .header(IF_NONE_MATCH, headers[ETAG].clone())
+        .body(Body::empty())
+        .unwrap();
+    let (status, headers, _) = send(&router, req).await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    assert_eq!(headers[X_CONFIG_STALE], "true");
+}